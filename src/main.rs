@@ -1,8 +1,16 @@
 extern crate clap;
+extern crate rayon;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+extern crate serde_json;
 extern crate users;
 
+use rayon::prelude::*;
 use users::{Users, Groups, UsersCache};
 use std::{io, fs, ffi};
+use std::cmp::Ordering;
+use std::collections::VecDeque;
 use std::path::{Path, PathBuf};
 use std::os::unix::fs::MetadataExt;
 use std::os::unix::ffi::OsStrExt;
@@ -10,6 +18,12 @@ use std::os::unix::ffi::OsStrExt;
 struct Args {
     command_mode: bool,
     directories: Vec<String>,
+    jobs: Option<usize>,
+    excludes: Vec<String>,
+    ignore_file: Option<String>,
+    save: Option<String>,
+    diff: Option<String>,
+    fail_on_error: bool,
 }
 
 fn parse_args() -> Args {
@@ -24,6 +38,34 @@ fn parse_args() -> Args {
         .arg(clap::Arg::with_name("commands")
              .help("display as a list of recursive chmods/chowns")
              .long("commands"))
+        .arg(clap::Arg::with_name("jobs")
+             .help("number of threads to use when scanning the filesystem in parallel (default: number of CPUs)")
+             .short("j")
+             .long("jobs")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("exclude")
+             .help("skip paths matching this gitignore-style pattern (may be repeated)")
+             .long("exclude")
+             .takes_value(true)
+             .number_of_values(1)
+             .multiple(true))
+        .arg(clap::Arg::with_name("ignore-file")
+             .help("skip paths matching the gitignore-style patterns in this file")
+             .long("ignore-file")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("save")
+             .help("save the scanned tree to <file> instead of printing it")
+             .long("save")
+             .takes_value(true)
+             .conflicts_with("diff"))
+        .arg(clap::Arg::with_name("diff")
+             .help("re-scan and report permission/ownership drift against a tree saved with --save")
+             .long("diff")
+             .takes_value(true)
+             .conflicts_with("save"))
+        .arg(clap::Arg::with_name("fail-on-error")
+             .help("exit with a nonzero status if any entry's metadata or directory listing couldn't be read")
+             .long("fail-on-error"))
         .get_matches();
 
     Args {
@@ -32,10 +74,117 @@ fn parse_args() -> Args {
                             .unwrap()
                             .map(|v| v.to_owned())
                             .collect(),
+        jobs: matches.value_of("jobs").map(|v| v.parse()
+                    .expect("permtree: error, --jobs expects a positive integer!")),
+        excludes: matches.values_of("exclude")
+                         .map(|vs| vs.map(|v| v.to_owned()).collect())
+                         .unwrap_or_default(),
+        ignore_file: matches.value_of("ignore-file").map(|v| v.to_owned()),
+        save: matches.value_of("save").map(|v| v.to_owned()),
+        diff: matches.value_of("diff").map(|v| v.to_owned()),
+        fail_on_error: matches.is_present("fail-on-error"),
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+/// A single gitignore-style exclude pattern.
+///
+/// A pattern containing a `/` anywhere but the end is anchored to the scan
+/// root; otherwise it matches a file/directory name at any depth. A
+/// trailing `/` restricts the pattern to directories.
+struct Pattern {
+    anchored: bool,
+    dir_only: bool,
+    glob: String,
+}
+
+impl Pattern {
+    fn parse(raw: &str) -> Pattern {
+        let mut glob = raw.trim();
+        let dir_only = glob.ends_with('/');
+        if dir_only {
+            glob = &glob[..glob.len() - 1];
+        }
+        let anchored = glob.contains('/');
+        let glob = glob.trim_start_matches('/');
+        Pattern {
+            anchored,
+            dir_only,
+            glob: glob.to_owned(),
+        }
+    }
+
+    fn matches(&self, rel_path: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            glob_match(&self.glob, &rel_path.to_string_lossy())
+        } else {
+            let name = rel_path.file_name()
+                               .map(|n| n.to_string_lossy().into_owned())
+                               .unwrap_or_default();
+            glob_match(&self.glob, &name)
+        }
+    }
+}
+
+/// Match `text` against a shell-style glob where `*` matches any run of
+/// characters (including none) and `?` matches exactly one character.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut pi, mut ti) = (0, 0);
+    let (mut star_pi, mut star_ti) = (None, 0);
+
+    while ti < text.len() {
+        if pi < pattern.len() && (pattern[pi] == '?' || pattern[pi] == text[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < pattern.len() && pattern[pi] == '*' {
+            star_pi = Some(pi);
+            star_ti = ti;
+            pi += 1;
+        } else if let Some(sp) = star_pi {
+            pi = sp + 1;
+            star_ti += 1;
+            ti = star_ti;
+        } else {
+            return false;
+        }
+    }
+    while pi < pattern.len() && pattern[pi] == '*' {
+        pi += 1;
+    }
+    pi == pattern.len()
+}
+
+/// Compiled set of exclude patterns consulted during `build_tree`.
+struct Matcher {
+    patterns: Vec<Pattern>,
+}
+
+impl Matcher {
+    fn from_args(excludes: &[String], ignore_file: &Option<String>) -> io::Result<Matcher> {
+        let mut patterns: Vec<Pattern> = excludes.iter().map(|p| Pattern::parse(p)).collect();
+        if let Some(path) = ignore_file {
+            let contents = fs::read_to_string(path)?;
+            for line in contents.lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                patterns.push(Pattern::parse(line));
+            }
+        }
+        Ok(Matcher { patterns })
+    }
+
+    fn is_excluded(&self, rel_path: &Path, is_dir: bool) -> bool {
+        self.patterns.iter().any(|p| p.matches(rel_path, is_dir))
+    }
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 enum FileKind {
     Directory,
     Leaf,
@@ -43,6 +192,11 @@ enum FileKind {
 
 #[derive(Debug)]
 struct NodeData {
+    // absolute values, regardless of what the parent has
+    perms: u32,
+    uid: u32,
+    gid: u32,
+
     override_perms: Option<u32>,
     override_uid: Option<u32>,
     override_gid: Option<u32>,
@@ -88,8 +242,10 @@ struct ParentData {
     kind: FileKind,
 }
 
-/// Read from the filesystem into an in-memory tree.
-fn build_tree(path: &Path, maybe_parent_data: &Option<ParentData>) -> Node {
+/// Read from the filesystem into an in-memory tree. `rel_path` is `path`'s
+/// location relative to the scan root, used to evaluate anchored exclude
+/// patterns in `matcher`.
+fn build_tree(path: &Path, maybe_parent_data: &Option<ParentData>, matcher: &Matcher, rel_path: &Path) -> Node {
     Node {
         name: path.file_name()
                   .expect("permtree: error, failed to get file name from path!")
@@ -107,6 +263,9 @@ fn build_tree(path: &Path, maybe_parent_data: &Option<ParentData>) -> Node {
             };
             let kind = if metadata.is_dir() { FileKind::Directory } else { FileKind::Leaf };
             NodeData {
+                perms,
+                uid: metadata.uid(),
+                gid: metadata.gid(),
                 override_perms,
                 override_uid,
                 override_gid,
@@ -119,8 +278,24 @@ fn build_tree(path: &Path, maybe_parent_data: &Option<ParentData>) -> Node {
                             gid: metadata.gid(),
                             kind,
                         });
-                        ls(path).map(|children| children.iter().map(|child|
-                            build_tree(child, &our_data)).collect())
+                        ls(path).map(|children| {
+                            let kept: Vec<(PathBuf, PathBuf)> = children.into_iter()
+                                .filter_map(|child| {
+                                    let name = child.file_name()?.to_owned();
+                                    let child_rel = rel_path.join(&name);
+                                    let is_dir = child.is_dir();
+                                    if matcher.is_excluded(&child_rel, is_dir) {
+                                        None
+                                    } else {
+                                        Some((child, child_rel))
+                                    }
+                                })
+                                .collect();
+                            kept.par_iter()
+                                .map(|(child, child_rel)|
+                                     build_tree(child, &our_data, matcher, child_rel))
+                                .collect()
+                        })
                     } else {
                         Ok(vec![])
                     }
@@ -133,7 +308,7 @@ fn build_tree(path: &Path, maybe_parent_data: &Option<ParentData>) -> Node {
 /// Prune away subtrees that have only inherited fields.
 fn prune(node: Node) -> Option<Node> {
     match node.data {
-        Ok(NodeData { override_perms, override_uid, override_gid, kind, children }) => {
+        Ok(NodeData { perms, uid, gid, override_perms, override_uid, override_gid, kind, children }) => {
             match children {
                 Ok(cs) => {
                     let all_inherited = override_perms.is_none()
@@ -146,6 +321,9 @@ fn prune(node: Node) -> Option<Node> {
                         Some(Node {
                             name: node.name,
                             data: Ok(NodeData {
+                                perms,
+                                uid,
+                                gid,
                                 override_perms,
                                 override_uid,
                                 override_gid,
@@ -158,6 +336,9 @@ fn prune(node: Node) -> Option<Node> {
                 Err(e) => Some(Node {
                     name: node.name,
                     data: Ok(NodeData {
+                        perms,
+                        uid,
+                        gid,
                         override_perms,
                         override_uid,
                         override_gid,
@@ -176,27 +357,71 @@ fn prune(node: Node) -> Option<Node> {
     }
 }
 
-/// Perform a preorder traversal of the tree. Apply the `visit`
-/// function at each node.
-fn preorder_traversal(node: &Node, depth: usize, visit: &mut FnMut(&Node, usize)) {
-    visit(node, depth);
-    if let Ok(NodeData { children: Ok(ref cs), .. }) = node.data {
-        for child in cs.iter() {
-            preorder_traversal(child, 1 + depth, visit);
+/// A preorder, allocation-bounded traversal of a `Node` tree with no
+/// recursion on the Rust call stack, so it can't stack overflow on
+/// pathologically deep directory trees.
+///
+/// Each step pops the front of the queue and pushes that node's children
+/// (tagged with their depth) back onto the front, in order, so the next
+/// pops still visit depth-first.
+struct NodeIter<'a> {
+    queue: VecDeque<(&'a Node, usize)>,
+}
+
+impl<'a> NodeIter<'a> {
+    fn new(root: &'a Node) -> NodeIter<'a> {
+        let mut queue = VecDeque::new();
+        queue.push_back((root, 0));
+        NodeIter { queue }
+    }
+}
+
+impl<'a> Iterator for NodeIter<'a> {
+    type Item = (&'a Node, usize);
+
+    fn next(&mut self) -> Option<(&'a Node, usize)> {
+        let (node, depth) = self.queue.pop_front()?;
+        if let Ok(NodeData { children: Ok(ref cs), .. }) = node.data {
+            for child in cs.iter().rev() {
+                self.queue.push_front((child, depth + 1));
+            }
         }
+        Some((node, depth))
     }
 }
 
-/// Print the tree to the terminal.
-fn display_tree(root: &Node) {
+/// A node is unreadable if its own metadata couldn't be read, or it's a
+/// directory whose listing couldn't be read.
+fn node_is_unreadable(node: &Node) -> bool {
+    match node.data {
+        Err(_) => true,
+        Ok(ref data) => data.children.is_err(),
+    }
+}
+
+/// Count entries whose metadata or directory listing could not be read, so
+/// callers can honor `--fail-on-error` even when they don't otherwise walk
+/// the tree themselves (e.g. `--save`/`--diff`).
+fn count_unreadable(root: &Node) -> usize {
+    NodeIter::new(root).filter(|&(node, _)| node_is_unreadable(node)).count()
+}
+
+/// Print the tree to the terminal. Returns the number of entries whose
+/// metadata or directory listing could not be read, so callers can report a
+/// summary and honor `--fail-on-error`.
+fn display_tree(root: &Node) -> usize {
     let mut output = String::new();
     let mut cache = NameCache::new();
-    {
-        let mut visit = |node: &Node, depth: usize| {
-            for i in 0..depth {
-                output.push_str("  ");
-            }
-            if let Ok(ref data) = node.data {
+    let mut unreadable = 0;
+    for (node, depth) in NodeIter::new(root) {
+        for _ in 0..depth {
+            output.push_str("  ");
+        }
+        if node_is_unreadable(node) {
+            unreadable += 1;
+        }
+        match node.data {
+            Ok(ref data) => {
                 output.push_str("[ ");
                 if let Some(perms) = data.override_perms {
                     output.push_str(&format!("perms: {:04o}, ", perms));
@@ -208,16 +433,23 @@ fn display_tree(root: &Node) {
                     output.push_str(&format!("group: {}, ", cache.display_gid(gid)));
                 }
                 output.push(']');
-            } else {
-                output.push_str(&" [ error reading metadata ]");
+                if let Err(ref e) = data.children {
+                    output.push_str(&format!(" [ unreadable: {} ]", e));
+                }
             }
-            output.push(' ');
-            output.push_str(&node.name.to_string_lossy());
-            output.push('\n');
-        };
-        preorder_traversal(root, 0, &mut visit);
+            Err(ref e) => {
+                output.push_str(&format!("[ unreadable: {} ]", e));
+            }
+        }
+        output.push(' ');
+        output.push_str(&node.name.to_string_lossy());
+        output.push('\n');
+    }
+    if unreadable > 0 {
+        output.push_str(&format!("{} entries could not be read\n", unreadable));
     }
     print!("{}", output);
+    unreadable
 }
 
 /// Printing characters is hard. Encode everything in hex for now.
@@ -258,19 +490,25 @@ impl NameCache {
     }
 }
 
-/// What commands are needed to reproduce this tree of permissions?
-fn display_commands(root: &Node) {
+/// What commands are needed to reproduce this tree of permissions? Returns
+/// the number of entries whose metadata or directory listing could not be
+/// read, so callers can report a summary and honor `--fail-on-error`.
+fn display_commands(root: &Node) -> usize {
     let mut output = String::new();
     let mut path = vec![];
     let mut cache = NameCache::new();
-    {
-        let mut visit = |node: &Node, depth: usize| {
-            if path.len() > depth {
-                path.drain(depth..);
-            }
-            path.push(node.name.to_owned());
-            let display_path = bash_encode(&path);
-            if let Ok(ref data) = node.data {
+    let mut unreadable = 0;
+    for (node, depth) in NodeIter::new(root) {
+        if path.len() > depth {
+            path.drain(depth..);
+        }
+        path.push(node.name.to_owned());
+        let display_path = bash_encode(&path);
+        if node_is_unreadable(node) {
+            unreadable += 1;
+        }
+        match node.data {
+            Ok(ref data) => {
                 match (data.override_uid, data.override_gid) {
                     (Some(uid), Some(gid)) => {
                         output.push_str(&format!("chown -R {}:{} {}\n",
@@ -295,15 +533,295 @@ fn display_commands(root: &Node) {
                     // setuid and setgid bits from directories.
                     output.push_str(&format!("chmod -R 0{:04o} {}\n", perms, display_path));
                 }
+                if let Err(ref e) = data.children {
+                    output.push_str(&format!("# could not read {}: {}\n", display_path, e));
+                }
+            }
+            Err(ref e) => {
+                output.push_str(&format!("# could not read {}: {}\n", display_path, e));
+            }
+        };
+    }
+    print!("{}", output);
+    unreadable
+}
+
+/// A serializable mirror of `Node`/`NodeData`, used to save a tree to disk
+/// and to reload one for `--diff`. Unlike `Node`, every field is absolute:
+/// there's no parent to inherit from once the tree is on disk.
+#[derive(Serialize, Deserialize, Debug)]
+struct SnapshotNode {
+    name: String,
+    data: SnapshotResult,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum SnapshotResult {
+    Ok { perms: u32, uid: u32, gid: u32, kind: FileKind, children: SnapshotChildren },
+    Err(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum SnapshotChildren {
+    Ok(Vec<SnapshotNode>),
+    Err(String),
+}
+
+fn node_to_snapshot(node: &Node) -> SnapshotNode {
+    SnapshotNode {
+        name: node.name.to_string_lossy().into_owned(),
+        data: match node.data {
+            Ok(ref data) => SnapshotResult::Ok {
+                perms: data.perms,
+                uid: data.uid,
+                gid: data.gid,
+                kind: data.kind,
+                children: match data.children {
+                    Ok(ref cs) => SnapshotChildren::Ok(cs.iter().map(node_to_snapshot).collect()),
+                    Err(ref e) => SnapshotChildren::Err(e.to_string()),
+                },
+            },
+            Err(ref e) => SnapshotResult::Err(e.to_string()),
+        },
+    }
+}
+
+/// Serialize `root` to `path` so a later `--diff <path>` can report drift.
+fn save_snapshot(root: &Node, path: &str) -> io::Result<()> {
+    let snapshot = node_to_snapshot(root);
+    let json = serde_json::to_string_pretty(&snapshot)?;
+    fs::write(path, json)
+}
+
+/// Load a tree previously written by `save_snapshot`.
+fn load_snapshot(path: &str) -> io::Result<SnapshotNode> {
+    let json = fs::read_to_string(path)?;
+    serde_json::from_str(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// What, if anything, changed about a node between two snapshots.
+#[derive(Debug)]
+enum DiffStatus {
+    Added,
+    Removed,
+    Changed { before: (u32, u32, u32), after: (u32, u32, u32) },
+    BecameUnreadable,
+    BecameReadable,
+    Unchanged,
+}
+
+#[derive(Debug)]
+struct DiffNode {
+    name: String,
+    status: DiffStatus,
+    children: Vec<DiffNode>,
+}
+
+/// Every descendant of `node`, all reported as the given status (used when a
+/// whole subtree only exists on one side of the diff).
+fn diff_subtree(node: &SnapshotNode, status_for: &Fn() -> DiffStatus) -> DiffNode {
+    let children = match node.data {
+        SnapshotResult::Ok { children: SnapshotChildren::Ok(ref cs), .. } =>
+            cs.iter().map(|c| diff_subtree(c, status_for)).collect(),
+        _ => vec![],
+    };
+    DiffNode { name: node.name.clone(), status: status_for(), children }
+}
+
+/// Merge-join two name-sorted child lists the way a status algorithm would,
+/// producing one `DiffNode` per name seen on either side.
+fn diff_children(old: &[SnapshotNode], new: &[SnapshotNode]) -> Vec<DiffNode> {
+    let mut old_sorted: Vec<&SnapshotNode> = old.iter().collect();
+    old_sorted.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut new_sorted: Vec<&SnapshotNode> = new.iter().collect();
+    new_sorted.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut result = vec![];
+    let (mut oi, mut ni) = (0, 0);
+    while oi < old_sorted.len() && ni < new_sorted.len() {
+        match old_sorted[oi].name.cmp(&new_sorted[ni].name) {
+            Ordering::Less => {
+                result.push(diff_subtree(old_sorted[oi], &|| DiffStatus::Removed));
+                oi += 1;
+            }
+            Ordering::Greater => {
+                result.push(diff_subtree(new_sorted[ni], &|| DiffStatus::Added));
+                ni += 1;
+            }
+            Ordering::Equal => {
+                result.push(diff_node(old_sorted[oi], new_sorted[ni]));
+                oi += 1;
+                ni += 1;
+            }
+        }
+    }
+    for o in &old_sorted[oi..] {
+        result.push(diff_subtree(o, &|| DiffStatus::Removed));
+    }
+    for n in &new_sorted[ni..] {
+        result.push(diff_subtree(n, &|| DiffStatus::Added));
+    }
+    result
+}
+
+/// Diff a single name present on both sides, recursing into its children.
+///
+/// A node that's unreadable on both sides is reported as `Unchanged` (it's
+/// still unreadable, nothing new to say), and an `Ok`/`Err` transition gets
+/// an honest `BecameUnreadable`/`BecameReadable` status rather than
+/// fabricated perms/uid/gid — recursing into whichever side still has
+/// children to show.
+fn diff_node(old: &SnapshotNode, new: &SnapshotNode) -> DiffNode {
+    let (status, children) = match (&old.data, &new.data) {
+        (&SnapshotResult::Ok { perms: op, uid: ou, gid: og, children: ref oc, .. },
+         &SnapshotResult::Ok { perms: np, uid: nu, gid: ng, children: ref nc, .. }) => {
+            let status = if (op, ou, og) == (np, nu, ng) {
+                DiffStatus::Unchanged
+            } else {
+                DiffStatus::Changed { before: (op, ou, og), after: (np, nu, ng) }
+            };
+            let children = match (oc, nc) {
+                (SnapshotChildren::Ok(ocs), SnapshotChildren::Ok(ncs)) => diff_children(ocs, ncs),
+                (SnapshotChildren::Ok(ocs), &SnapshotChildren::Err(_)) =>
+                    ocs.iter().map(|c| diff_subtree(c, &|| DiffStatus::Removed)).collect(),
+                (&SnapshotChildren::Err(_), SnapshotChildren::Ok(ncs)) =>
+                    ncs.iter().map(|c| diff_subtree(c, &|| DiffStatus::Added)).collect(),
+                (&SnapshotChildren::Err(_), &SnapshotChildren::Err(_)) => vec![],
+            };
+            (status, children)
+        }
+        (&SnapshotResult::Err(_), &SnapshotResult::Err(_)) => (DiffStatus::Unchanged, vec![]),
+        (SnapshotResult::Ok { children: oc, .. }, &SnapshotResult::Err(_)) => {
+            let children = match oc {
+                SnapshotChildren::Ok(ocs) => ocs.iter().map(|c| diff_subtree(c, &|| DiffStatus::Removed)).collect(),
+                SnapshotChildren::Err(_) => vec![],
+            };
+            (DiffStatus::BecameUnreadable, children)
+        }
+        (&SnapshotResult::Err(_), SnapshotResult::Ok { children: nc, .. }) => {
+            let children = match nc {
+                SnapshotChildren::Ok(ncs) => ncs.iter().map(|c| diff_subtree(c, &|| DiffStatus::Added)).collect(),
+                SnapshotChildren::Err(_) => vec![],
             };
+            (DiffStatus::BecameReadable, children)
+        }
+    };
+    DiffNode { name: new.name.clone(), status, children }
+}
+
+/// Prune away subtrees that are unchanged top to bottom, the same way
+/// `prune` drops subtrees with only inherited fields.
+fn prune_diff(node: DiffNode) -> Option<DiffNode> {
+    let new_children: Vec<_> = node.children.into_iter().filter_map(prune_diff).collect();
+    if let DiffStatus::Unchanged = node.status {
+        if new_children.is_empty() {
+            return None;
+        }
+    }
+    Some(DiffNode { name: node.name, status: node.status, children: new_children })
+}
+
+/// Perform a preorder traversal of a diff tree. Apply the `visit` function at
+/// each node.
+fn diff_preorder(node: &DiffNode, depth: usize, visit: &mut FnMut(&DiffNode, usize)) {
+    visit(node, depth);
+    for child in &node.children {
+        diff_preorder(child, 1 + depth, visit);
+    }
+}
+
+/// Print a diff tree to the terminal.
+fn display_diff_tree(root: &DiffNode) {
+    let mut output = String::new();
+    let mut cache = NameCache::new();
+    {
+        let mut visit = |node: &DiffNode, depth: usize| {
+            for _ in 0..depth {
+                output.push_str("  ");
+            }
+            match node.status {
+                DiffStatus::Added => output.push_str("+ added "),
+                DiffStatus::Removed => output.push_str("- removed "),
+                DiffStatus::Changed { before, after } => {
+                    output.push_str("~ changed [ ");
+                    if before.0 != after.0 {
+                        output.push_str(&format!("perms: {:04o} -> {:04o}, ", before.0, after.0));
+                    }
+                    if before.1 != after.1 {
+                        output.push_str(&format!("user: {} -> {}, ", cache.display_uid(before.1), cache.display_uid(after.1)));
+                    }
+                    if before.2 != after.2 {
+                        output.push_str(&format!("group: {} -> {}, ", cache.display_gid(before.2), cache.display_gid(after.2)));
+                    }
+                    output.push_str("] ");
+                }
+                DiffStatus::BecameUnreadable => output.push_str("~ became unreadable "),
+                DiffStatus::BecameReadable => output.push_str("~ became readable "),
+                DiffStatus::Unchanged => (),
+            }
+            output.push_str(&node.name);
+            output.push('\n');
         };
-        preorder_traversal(root, 0, &mut visit);
+        diff_preorder(root, 0, &mut visit);
+    }
+    print!("{}", output);
+}
+
+/// Render a diff tree as chmod/chown commands that restore the saved
+/// (`before`) permissions and ownership, so the diff doubles as a
+/// remediation script. Paths that were added or removed have nothing to
+/// restore, so they're emitted as comments instead.
+fn display_diff_commands(root: &DiffNode) {
+    let mut output = String::new();
+    let mut path: Vec<ffi::OsString> = vec![];
+    let mut cache = NameCache::new();
+    {
+        let mut visit = |node: &DiffNode, depth: usize| {
+            if path.len() > depth {
+                path.drain(depth..);
+            }
+            path.push(ffi::OsString::from(&node.name));
+            let display_path = bash_encode(&path);
+            match node.status {
+                DiffStatus::Added => {
+                    output.push_str(&format!("# added: {}\n", display_path));
+                }
+                DiffStatus::Removed => {
+                    output.push_str(&format!("# removed: {}\n", display_path));
+                }
+                DiffStatus::Changed { before, .. } => {
+                    output.push_str(&format!("chown {}:{} {}\n",
+                                             cache.display_uid(before.1),
+                                             cache.display_gid(before.2),
+                                             display_path));
+                    // an extra leading 0 tells GNU chmod that it's OK to remove
+                    // setuid and setgid bits from directories.
+                    output.push_str(&format!("chmod 0{:04o} {}\n", before.0, display_path));
+                }
+                DiffStatus::BecameUnreadable => {
+                    output.push_str(&format!("# became unreadable, nothing to restore: {}\n", display_path));
+                }
+                DiffStatus::BecameReadable => {
+                    output.push_str(&format!("# newly readable, no prior state to restore: {}\n", display_path));
+                }
+                DiffStatus::Unchanged => (),
+            }
+        };
+        diff_preorder(root, 0, &mut visit);
     }
     print!("{}", output);
 }
 
 fn main() {
     let args = parse_args();
+    if let Some(jobs) = args.jobs {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build_global()
+            .expect("permtree: error, failed to set up the thread pool!");
+    }
+    let matcher = Matcher::from_args(&args.excludes, &args.ignore_file)
+        .expect("permtree: error, failed to read --ignore-file!");
     let mut paths = vec![];
     for name in args.directories {
         let path = Path::new(&name);
@@ -314,13 +832,235 @@ fn main() {
             return;
         }
     }
-    
+
+    if args.save.is_some() || args.diff.is_some() {
+        if paths.len() != 1 {
+            println!("permtree: error: --save/--diff require exactly one directory");
+            return;
+        }
+        let root = build_tree(&paths[0], &None, &matcher, Path::new(""));
+        let unreadable = count_unreadable(&root);
+        if let Some(save_path) = args.save {
+            save_snapshot(&root, &save_path)
+                .expect("permtree: error, failed to write --save file!");
+        } else if let Some(diff_path) = args.diff {
+            let old = load_snapshot(&diff_path)
+                .expect("permtree: error, failed to read --diff file!");
+            let new = node_to_snapshot(&root);
+            match prune_diff(diff_node(&old, &new)) {
+                Some(diff) => {
+                    if args.command_mode {
+                        display_diff_commands(&diff);
+                    } else {
+                        display_diff_tree(&diff);
+                    }
+                }
+                None => println!("permtree: no changes since {}", diff_path),
+            }
+        }
+        if args.fail_on_error && unreadable > 0 {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let mut unreadable = 0;
     for pathbuf in paths.iter() {
-        let root = build_tree(&pathbuf, &None);
-        if args.command_mode {
-            display_commands(&root);
+        let root = build_tree(&pathbuf, &None, &matcher, Path::new(""));
+        unreadable += if args.command_mode {
+            display_commands(&root)
         } else {
-            display_tree(&root);
+            display_tree(&root)
+        };
+    }
+    if args.fail_on_error && unreadable > 0 {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod node_tests {
+    use super::*;
+
+    fn ok_leaf(name: &str) -> Node {
+        Node {
+            name: name.into(),
+            data: Ok(NodeData {
+                perms: 0o644,
+                uid: 0,
+                gid: 0,
+                override_perms: None,
+                override_uid: None,
+                override_gid: None,
+                kind: FileKind::Leaf,
+                children: Ok(vec![]),
+            }),
         }
     }
+
+    fn ok_dir(name: &str, children: Vec<Node>) -> Node {
+        Node {
+            name: name.into(),
+            data: Ok(NodeData {
+                perms: 0o755,
+                uid: 0,
+                gid: 0,
+                override_perms: None,
+                override_uid: None,
+                override_gid: None,
+                kind: FileKind::Directory,
+                children: Ok(children),
+            }),
+        }
+    }
+
+    fn err_node(name: &str) -> Node {
+        Node { name: name.into(), data: Err(io::Error::new(io::ErrorKind::PermissionDenied, "denied")) }
+    }
+
+    fn unreadable_children_dir(name: &str) -> Node {
+        Node {
+            name: name.into(),
+            data: Ok(NodeData {
+                perms: 0o755,
+                uid: 0,
+                gid: 0,
+                override_perms: None,
+                override_uid: None,
+                override_gid: None,
+                kind: FileKind::Directory,
+                children: Err(io::Error::new(io::ErrorKind::PermissionDenied, "denied")),
+            }),
+        }
+    }
+
+    #[test]
+    fn counts_both_kinds_of_unreadable_node() {
+        let root = ok_dir("root", vec![
+            ok_leaf("a.txt"),
+            err_node("b.txt"),
+            unreadable_children_dir("sub"),
+        ]);
+        assert_eq!(count_unreadable(&root), 2);
+    }
+
+    #[test]
+    fn readable_tree_counts_zero() {
+        let root = ok_dir("root", vec![ok_leaf("a.txt"), ok_dir("sub", vec![ok_leaf("b.txt")])]);
+        assert_eq!(count_unreadable(&root), 0);
+    }
+}
+
+#[cfg(test)]
+mod matcher_tests {
+    use super::*;
+
+    #[test]
+    fn glob_star_and_question_mark() {
+        assert!(glob_match("*.txt", "notes.txt"));
+        assert!(glob_match("*.txt", ".txt"));
+        assert!(!glob_match("*.txt", "notes.txt.bak"));
+        assert!(glob_match("no?e.txt", "note.txt"));
+        assert!(!glob_match("no?e.txt", "noooe.txt"));
+        assert!(glob_match("a*b*c", "axxbyyc"));
+        assert!(!glob_match("a*b*c", "axxbyy"));
+    }
+
+    #[test]
+    fn unanchored_pattern_matches_basename_at_any_depth() {
+        let pattern = Pattern::parse("*.log");
+        assert!(pattern.matches(Path::new("out.log"), false));
+        assert!(pattern.matches(Path::new("deep/nested/out.log"), false));
+        assert!(!pattern.matches(Path::new("out.txt"), false));
+    }
+
+    #[test]
+    fn anchored_pattern_matches_only_at_scan_root() {
+        let pattern = Pattern::parse("build/out.log");
+        assert!(pattern.matches(Path::new("build/out.log"), false));
+        assert!(!pattern.matches(Path::new("other/build/out.log"), false));
+    }
+
+    #[test]
+    fn dir_only_pattern_skips_files() {
+        let pattern = Pattern::parse("target/");
+        assert!(pattern.matches(Path::new("target"), true));
+        assert!(!pattern.matches(Path::new("target"), false));
+    }
+
+    #[test]
+    fn matcher_checks_all_patterns() {
+        let matcher = Matcher::from_args(
+            &["*.log".to_owned(), "target/".to_owned()],
+            &None,
+        ).unwrap();
+        assert!(matcher.is_excluded(Path::new("out.log"), false));
+        assert!(matcher.is_excluded(Path::new("target"), true));
+        assert!(!matcher.is_excluded(Path::new("target"), false));
+        assert!(!matcher.is_excluded(Path::new("src/main.rs"), false));
+    }
+}
+
+#[cfg(test)]
+mod diff_tests {
+    use super::*;
+
+    fn ok_leaf(name: &str, perms: u32, uid: u32, gid: u32) -> SnapshotNode {
+        SnapshotNode {
+            name: name.to_owned(),
+            data: SnapshotResult::Ok {
+                perms, uid, gid,
+                kind: FileKind::Leaf,
+                children: SnapshotChildren::Ok(vec![]),
+            },
+        }
+    }
+
+    fn ok_dir(name: &str, perms: u32, uid: u32, gid: u32, children: Vec<SnapshotNode>) -> SnapshotNode {
+        SnapshotNode {
+            name: name.to_owned(),
+            data: SnapshotResult::Ok {
+                perms, uid, gid,
+                kind: FileKind::Directory,
+                children: SnapshotChildren::Ok(children),
+            },
+        }
+    }
+
+    fn err_leaf(name: &str, message: &str) -> SnapshotNode {
+        SnapshotNode { name: name.to_owned(), data: SnapshotResult::Err(message.to_owned()) }
+    }
+
+    #[test]
+    fn unreadable_on_both_sides_is_unchanged() {
+        let old = err_leaf("link", "No such file or directory");
+        // A different error message on the new side shouldn't matter either:
+        // it's still unreadable, there's nothing new to report.
+        let new = err_leaf("link", "Permission denied");
+        let diff = diff_node(&old, &new);
+        assert!(matches!(diff.status, DiffStatus::Unchanged));
+        assert!(prune_diff(diff).is_none());
+    }
+
+    #[test]
+    fn becoming_unreadable_is_reported_honestly_and_recurses_into_old_children() {
+        let old = ok_dir("sub", 0o755, 0, 0, vec![ok_leaf("file.txt", 0o644, 0, 0)]);
+        let new = err_leaf("sub", "Permission denied");
+        let diff = diff_node(&old, &new);
+        assert!(matches!(diff.status, DiffStatus::BecameUnreadable));
+        assert_eq!(diff.children.len(), 1);
+        assert!(matches!(diff.children[0].status, DiffStatus::Removed));
+        assert!(prune_diff(diff).is_some());
+    }
+
+    #[test]
+    fn becoming_readable_is_reported_honestly_and_recurses_into_new_children() {
+        let old = err_leaf("sub", "Permission denied");
+        let new = ok_dir("sub", 0o755, 0, 0, vec![ok_leaf("file.txt", 0o644, 0, 0)]);
+        let diff = diff_node(&old, &new);
+        assert!(matches!(diff.status, DiffStatus::BecameReadable));
+        assert_eq!(diff.children.len(), 1);
+        assert!(matches!(diff.children[0].status, DiffStatus::Added));
+        assert!(prune_diff(diff).is_some());
+    }
 }